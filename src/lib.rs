@@ -1,49 +1,191 @@
 #![feature(test)]
 
+extern crate image;
+extern crate rayon;
+
 #[cfg(test)]
 extern crate png;
 #[cfg(test)]
 extern crate test;
 
-trait Pixel {
+/// A pixel color representation usable by the xBR filter.
+///
+/// Channels are exposed as normalized `0.0..=1.0` floats so `diff`/`blend`
+/// and the YUV weighting stay independent of the underlying bit depth.
+/// `Buffer` is the concrete type stored in the output buffer produced by
+/// `from_f32` — for `u32` and `Rgba64` it is simply `Self`, while the
+/// palette-indexed `IndexedColor` decodes from an index-tagged color but
+/// encodes into a plain `u32`.
+pub trait Color: Copy + Default {
+    type Buffer: Copy + Default;
+
     fn red_f32(&self) -> f32;
     fn green_f32(&self) -> f32;
     fn blue_f32(&self) -> f32;
-    fn red_u8(&self) -> u8;
-    fn green_u8(&self) -> u8;
-    fn blue_u8(&self) -> u8;
-}
+    fn alpha_f32(&self) -> f32;
 
-fn color_f32_to_u32(r: f32, g: f32, b: f32) -> u32 {
-    color_u8_to_u32(
-        (r as u32 & 0xFF) as u8,
-        (g as u32 & 0xFF) as u8,
-        (b as u32 & 0xFF) as u8,
-    )
+    fn from_f32(r: f32, g: f32, b: f32, a: f32) -> Self::Buffer;
+
+    /// Identity used by `XbrConfig::index_equality` mode. Colors that don't
+    /// come from a palette (the common case) have no index, so `diff` falls
+    /// back to the normal YUV comparison for them.
+    fn index_key(&self) -> Option<u32> {
+        None
+    }
 }
 
 fn color_u8_to_u32(r: u8, g: u8, b: u8) -> u32 {
-    (r as u32 & 0xFF) << 16 | (g as u32 & 0xFF) << 8 | (b as u32 & 0xFF)
+    color_rgba_u8_to_u32(r, g, b, 0xFF)
+}
+
+fn color_rgba_u8_to_u32(r: u8, g: u8, b: u8, a: u8) -> u32 {
+    (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | (b as u32)
+}
+
+fn color_rgba_u16_to_u64(r: u16, g: u16, b: u16, a: u16) -> u64 {
+    (a as u64) << 48 | (r as u64) << 32 | (g as u64) << 16 | (b as u64)
+}
+
+impl Color for u32 {
+    type Buffer = u32;
+
+    fn red_f32(&self) -> f32 {
+        (((self & 0xFF0000) >> 16) as u8 as f32) / 255.0
+    }
+    fn green_f32(&self) -> f32 {
+        (((self & 0x00FF00) >> 8) as u8 as f32) / 255.0
+    }
+    fn blue_f32(&self) -> f32 {
+        ((self & 0x0000FF) as u8 as f32) / 255.0
+    }
+    fn alpha_f32(&self) -> f32 {
+        (((self & 0xFF000000) >> 24) as u8 as f32) / 255.0
+    }
+
+    fn from_f32(r: f32, g: f32, b: f32, a: f32) -> u32 {
+        color_rgba_u8_to_u32(
+            (r * 255.0).round() as u8,
+            (g * 255.0).round() as u8,
+            (b * 255.0).round() as u8,
+            (a * 255.0).round() as u8,
+        )
+    }
+}
+
+/// 16-bit-per-channel RGBA color, packed into a `u64` the same way `u32`
+/// packs 8-bit channels. Lets callers upscale `RGB16_BE`/`RGBA16_BE` images
+/// (e.g. as decoded by `imagine`) without quantizing down to 8 bits first.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Rgba64(u64);
+
+impl Rgba64 {
+    /// Builds an `Rgba64` from a pre-packed value (bits 0-15 blue, 16-31
+    /// green, 32-47 red, 48-63 alpha), matching `color_rgba_u16_to_u64`.
+    pub fn new(packed: u64) -> Rgba64 {
+        Rgba64(packed)
+    }
+
+    /// The underlying packed `u64` representation.
+    pub fn packed(self) -> u64 {
+        self.0
+    }
+}
+
+impl Color for Rgba64 {
+    type Buffer = Rgba64;
+
+    fn red_f32(&self) -> f32 {
+        (((self.0 & 0x0000_FFFF_0000_0000) >> 32) as u16 as f32) / 65535.0
+    }
+    fn green_f32(&self) -> f32 {
+        (((self.0 & 0x0000_0000_FFFF_0000) >> 16) as u16 as f32) / 65535.0
+    }
+    fn blue_f32(&self) -> f32 {
+        ((self.0 & 0x0000_0000_0000_FFFF) as u16 as f32) / 65535.0
+    }
+    fn alpha_f32(&self) -> f32 {
+        (((self.0 & 0xFFFF_0000_0000_0000) >> 48) as u16 as f32) / 65535.0
+    }
+
+    fn from_f32(r: f32, g: f32, b: f32, a: f32) -> Rgba64 {
+        Rgba64(color_rgba_u16_to_u64(
+            (r * 65535.0).round() as u16,
+            (g * 65535.0).round() as u16,
+            (b * 65535.0).round() as u16,
+            (a * 65535.0).round() as u16,
+        ))
+    }
 }
 
-impl Pixel for u32 {
+/// A palette-indexed pixel: a resolved `u32` color tagged with the index it
+/// came from. Carrying the index alongside the color lets `diff` compare
+/// indices directly (see `XbrConfig::index_equality`) instead of going
+/// through the YUV distance, while still blending like any other `u32`
+/// color since palette entries near but not equal to each other should
+/// still count as a hard edge for classic sprite art.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+struct IndexedColor {
+    index: u8,
+    color: u32,
+}
+
+impl Color for IndexedColor {
+    type Buffer = u32;
+
     fn red_f32(&self) -> f32 {
-        self.red_u8() as f32
+        self.color.red_f32()
     }
     fn green_f32(&self) -> f32 {
-        self.green_u8() as f32
+        self.color.green_f32()
     }
     fn blue_f32(&self) -> f32 {
-        self.blue_u8() as f32
+        self.color.blue_f32()
     }
-    fn red_u8(&self) -> u8 {
-        ((self & 0xFF0000) >> 16) as u8
+    fn alpha_f32(&self) -> f32 {
+        self.color.alpha_f32()
     }
-    fn green_u8(&self) -> u8 {
-        ((self & 0x00FF00) >> 8) as u8
+
+    fn from_f32(r: f32, g: f32, b: f32, a: f32) -> u32 {
+        u32::from_f32(r, g, b, a)
     }
-    fn blue_u8(&self) -> u8 {
-        (self & 0x0000FF) as u8
+
+    fn index_key(&self) -> Option<u32> {
+        Some(self.index as u32)
+    }
+}
+
+/// Tunable parameters for the xBR edge-detection and blending steps.
+///
+/// The luminance/chroma weights control how strongly `diff` reacts to
+/// brightness versus color changes; `a_weight` does the same for the alpha
+/// channel, tuned independently of `y_weight` so transparency sensitivity
+/// can be adjusted without also changing luminance sensitivity. `blend_alpha`
+/// is how far `blend` moves a newly detected edge pixel toward its neighbor.
+/// `index_equality` only affects palette-indexed input (see `apply_indexed`):
+/// when set, two samples are considered identical iff their palette indices
+/// match, short-circuiting the YUV `diff` to 0 or "maximally different"
+/// instead of comparing resolved colors. Defaults match the values this
+/// filter originally shipped with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct XbrConfig {
+    pub y_weight: f32,
+    pub u_weight: f32,
+    pub v_weight: f32,
+    pub a_weight: f32,
+    pub blend_alpha: f32,
+    pub index_equality: bool,
+}
+
+impl Default for XbrConfig {
+    fn default() -> Self {
+        XbrConfig {
+            y_weight: 48.0,
+            u_weight: 7.0,
+            v_weight: 6.0,
+            a_weight: 48.0,
+            blend_alpha: 0.5,
+            index_equality: false,
+        }
     }
 }
 
@@ -54,52 +196,285 @@ impl Pixel for u32 {
 /// 1. Finds absolute color diference between two pixels.
 /// 2. Converts color difference into Y'UV, seperating color from light.
 /// 3. Applies Y'UV thresholds, giving importance to luminance.
-fn diff<T: Pixel>(pixel_a: T, pixel_b: T) -> f32 {
-    // Weights should emphasize luminance (Y), in order to work. Feel free to experiment.
-    const Y_WEIGHT: f32 = 48.0;
-    const U_WEIGHT: f32 = 7.0;
-    const V_WEIGHT: f32 = 6.0;
-
-    let r = (pixel_a.red_f32() - pixel_b.red_f32()).abs();
-    let b = (pixel_a.blue_f32() - pixel_b.blue_f32()).abs();
-    let g = (pixel_a.green_f32() - pixel_b.green_f32()).abs();
+fn diff<C: Color>(pixel_a: C, pixel_b: C, config: &XbrConfig) -> f32 {
+    // A fully transparent pixel has no meaningful color, so a transparent/opaque
+    // boundary must always be treated as a hard edge instead of falling through
+    // to the (meaningless) RGB comparison below.
+    const MAX_DIFF: f32 = 1.0e9;
+
+    if config.index_equality {
+        if let (Some(index_a), Some(index_b)) = (pixel_a.index_key(), pixel_b.index_key()) {
+            return if index_a == index_b { 0.0 } else { MAX_DIFF };
+        }
+    }
+
+    let transparent_a = pixel_a.alpha_f32() == 0.0;
+    let transparent_b = pixel_b.alpha_f32() == 0.0;
+
+    if transparent_a != transparent_b {
+        return MAX_DIFF;
+    }
+
+    // Channels are normalized (0.0..=1.0), so scale back up to the 0..=255
+    // range the YUV weights were tuned against, regardless of bit depth.
+    let r = (pixel_a.red_f32() - pixel_b.red_f32()).abs() * 255.0;
+    let g = (pixel_a.green_f32() - pixel_b.green_f32()).abs() * 255.0;
+    let b = (pixel_a.blue_f32() - pixel_b.blue_f32()).abs() * 255.0;
+    let a = (pixel_a.alpha_f32() - pixel_b.alpha_f32()).abs() * 255.0;
     let y = r * 0.299000 + g * 0.587000 + b * 0.114000;
     let u = r * -0.168736 + g * -0.331264 + b * 0.500000;
     let v = r * 0.500000 + g * -0.418688 + b * -0.081312;
-    let weight = (y * Y_WEIGHT) + (u * U_WEIGHT) + (v * V_WEIGHT);
+    let weight = (y * config.y_weight)
+        + (u * config.u_weight)
+        + (v * config.v_weight)
+        + (a * config.a_weight);
     weight
 }
 
 /// Blends two pixels together and retuns an new Pixel.
-fn blend<T: Pixel>(pixel_a: T, pixel_b: T, alpha: f32) -> u32 {
+fn blend<C: Color>(pixel_a: C, pixel_b: C, alpha: f32) -> C::Buffer {
     let reverse_alpha = 1.0 - alpha;
 
-    color_f32_to_u32(
+    C::from_f32(
         (alpha * pixel_b.red_f32()) + (reverse_alpha * pixel_a.red_f32()),
         (alpha * pixel_b.green_f32()) + (reverse_alpha * pixel_a.green_f32()),
         (alpha * pixel_b.blue_f32()) + (reverse_alpha * pixel_a.blue_f32()),
+        (alpha * pixel_b.alpha_f32()) + (reverse_alpha * pixel_a.alpha_f32()),
     )
 }
 
-/// Applies the xBR filter.
-pub fn apply(buf: &mut [u32], image: &[u32], width: u32, height: u32) {
-    const SCALE: i32 = 2;
+/// Applies the xBR filter using the default `XbrConfig`, at the classic 2x scale.
+pub fn apply<C: Color>(buf: &mut [C::Buffer], image: &[C], width: u32, height: u32) {
+    apply_with_config(buf, image, width, height, &XbrConfig::default());
+}
+
+/// Applies the xBR filter with custom edge-sensitivity/blend-strength weights,
+/// at the classic 2x scale.
+pub fn apply_with_config<C: Color>(
+    buf: &mut [C::Buffer],
+    image: &[C],
+    width: u32,
+    height: u32,
+    config: &XbrConfig,
+) {
+    apply_rows(
+        buf,
+        image,
+        width,
+        height,
+        (0, height as i32),
+        &RowParams { config, scale: 2 },
+    );
+}
+
+/// Applies the xBR filter at an arbitrary `scale` (2x, 3x, 4x, ...) in a
+/// single pass: each source pixel still resolves the same four corner
+/// edge-detection rules, but every subpixel of the `scale x scale` output
+/// grid gets its own distance-weighted blend toward the detected diagonal
+/// instead of the flat half-and-half split used at 2x. At `scale == 2` this
+/// degenerates exactly to `apply_with_config`.
+pub fn apply_scale<C: Color>(
+    buf: &mut [C::Buffer],
+    image: &[C],
+    width: u32,
+    height: u32,
+    scale: u32,
+) {
+    apply_scale_with_config(buf, image, width, height, scale, &XbrConfig::default());
+}
+
+/// `apply_scale`, but with custom edge-sensitivity/blend-strength weights.
+pub fn apply_scale_with_config<C: Color>(
+    buf: &mut [C::Buffer],
+    image: &[C],
+    width: u32,
+    height: u32,
+    scale: u32,
+    config: &XbrConfig,
+) {
+    apply_rows(
+        buf,
+        image,
+        width,
+        height,
+        (0, height as i32),
+        &RowParams {
+            config,
+            scale: scale as i32,
+        },
+    );
+}
+
+/// `apply_scale`, but for `scale == 4` runs the existing 2x pass twice
+/// (upscaling the 2x output again) instead of filtering in a single pass.
+/// Cheaper to reason about and reuses the well-tested 2x path, at the cost
+/// of softer diagonals than the single-pass `apply_scale`. `scale == 2`
+/// just forwards to `apply`; any other scale panics.
+pub fn apply_scale_multi_pass<C: Color<Buffer = C>>(
+    buf: &mut [C],
+    image: &[C],
+    width: u32,
+    height: u32,
+    scale: u32,
+) {
+    match scale {
+        2 => apply(buf, image, width, height),
+        4 => {
+            let (mut intermediate, mid_width, mid_height) =
+                get_buffer_for_size::<C>(width, height, 2);
+            apply(&mut intermediate[..], image, width, height);
+            apply(buf, &intermediate, mid_width, mid_height);
+        }
+        _ => panic!(
+            "apply_scale_multi_pass only supports scale 2 or 4, got {}",
+            scale
+        ),
+    }
+}
+
+/// Applies the xBR filter to a palette-indexed image, resolving each index
+/// against `palette` before filtering and writing the upscaled true-color
+/// result into `buf`. With `index_equality` set, two samples only count as
+/// identical when their palette indices match, which is the semantically
+/// correct behavior for sprite art where near-but-distinct palette entries
+/// should still be treated as a hard edge.
+pub fn apply_indexed(
+    buf: &mut [u32],
+    indices: &[u8],
+    palette: &[u32],
+    width: u32,
+    height: u32,
+    index_equality: bool,
+) {
+    let image: Vec<IndexedColor> = indices
+        .iter()
+        .map(|&index| IndexedColor {
+            index,
+            color: palette[index as usize],
+        })
+        .collect();
+
+    let config = XbrConfig {
+        index_equality,
+        ..XbrConfig::default()
+    };
+
+    apply_with_config(buf, &image, width, height, &config);
+}
+
+/// Bundles the two parameters that `apply_rows` and `write_quadrant` thread
+/// through together unchanged, keeping both functions' argument counts
+/// under `clippy::too_many_arguments`.
+struct RowParams<'a> {
+    config: &'a XbrConfig,
+    scale: i32,
+}
+
+/// The half of a `scale x scale` output grid a subpixel offset falls into
+/// along one axis (`0` = the half nearer the matrix's previous neighbor,
+/// `1` = the half nearer its next neighbor).
+fn subpixel_half(offset: u32, scale: u32) -> u32 {
+    if (offset as f32 + 0.5) < (scale as f32 / 2.0) {
+        0
+    } else {
+        1
+    }
+}
+
+/// Writes one quadrant (top-left, top-right, bottom-left or bottom-right)
+/// of a source pixel's `scale x scale` output grid.
+///
+/// `half_x`/`half_y` select which quadrant (`0` or `1` along each axis).
+/// `edge_pixel` is the neighbor chosen by the corner's edge-detection rule,
+/// or `None` when no edge was detected (the whole quadrant then just keeps
+/// the center color). Each subpixel inside the quadrant is blended toward
+/// `edge_pixel` in proportion to its distance from the pixel's own center —
+/// the subpixel right next to the center barely moves, the one at the
+/// outer corner moves the most, by `2 * distance * config.blend_alpha`
+/// (capped at 1). At `scale == 2` a quadrant is exactly one subpixel sitting
+/// at distance 0.5, so it moves by exactly `config.blend_alpha`, matching the
+/// original fixed 2x behavior.
+fn write_quadrant<C: Color>(
+    buf: &mut [C::Buffer],
+    scaled_width: i32,
+    base: (i32, i32),
+    half: (u32, u32),
+    center: C,
+    edge_pixel: Option<C>,
+    params: &RowParams,
+) {
+    let (base_x, base_y) = base;
+    let (half_x, half_y) = half;
+    let scale = params.scale;
+    let half_scale = scale as f32 / 2.0;
+
+    for sub_x in 0..scale as u32 {
+        if subpixel_half(sub_x, scale as u32) != half_x {
+            continue;
+        }
+        for sub_y in 0..scale as u32 {
+            if subpixel_half(sub_y, scale as u32) != half_y {
+                continue;
+            }
+
+            let dx = ((sub_x as f32 + 0.5) - half_scale).abs();
+            let dy = ((sub_y as f32 + 0.5) - half_scale).abs();
+            let distance = ((dx + dy) / (2.0 * half_scale)).min(1.0);
+
+            let value = match edge_pixel {
+                Some(neighbor) => {
+                    let alpha = (2.0 * distance * params.config.blend_alpha).min(1.0);
+                    blend(center, neighbor, alpha)
+                }
+                None => blend(center, center, 0.0),
+            };
+
+            let out_x = base_x * scale + sub_x as i32;
+            let out_y = base_y * scale + sub_y as i32;
+            buf[(out_y * scaled_width + out_x) as usize] = value;
+        }
+    }
+}
 
+/// Runs the xBR filter over source rows `y_start..y_end` at the given
+/// `scale`, writing into a `buf` that starts at the corresponding scaled
+/// row (i.e. `buf[0]` corresponds to source row `y_start`). `image` is
+/// always the full, unsliced source — `pixel_at` reads across the whole
+/// image (clamping out-of-bounds coordinates to the nearest edge pixel), so
+/// a halo of rows just outside `y_start..y_end` is available without the caller needing
+/// to overlap slices. This split lets `apply`/`apply_scale`/`apply_parallel`
+/// share one implementation while each tile writes into a disjoint
+/// sub-slice of the destination buffer.
+fn apply_rows<C: Color>(
+    buf: &mut [C::Buffer],
+    image: &[C],
+    width: u32,
+    height: u32,
+    rows: (i32, i32),
+    params: &RowParams,
+) {
+    let (y_start, y_end) = rows;
+    let config = params.config;
+    let scale = params.scale;
     let src_width = width as i32;
     let src_height = height as i32;
-    let scaled_width = src_width * SCALE;
+    let scaled_width = src_width * scale;
 
+    // Clamp to the edge instead of padding with `C::default()`: a synthetic
+    // default pixel is fully transparent (and, for indexed input, index 0),
+    // so comparing it against real in-bounds content via `diff` would read
+    // as a hard transparency/index edge and punch a false border into
+    // otherwise-flat opaque images.
     let pixel_at = |x: i32, y: i32| {
-        if x < 0 || x >= src_width || y < 0 || y >= src_height {
-            0
-        } else {
-            image[(src_width * y + x) as usize]
-        }
+        let x = x.max(0).min(src_width - 1);
+        let y = y.max(0).min(src_height - 1);
+        image[(src_width * y + x) as usize]
     };
 
-    let matrix = &mut [0; 21];
+    let matrix = &mut [C::default(); 21];
 
-    for y in 0..src_height {
+    for y in y_start..y_end {
+        let local_y = y - y_start;
         for x in 0..src_width {
             // Matrix: 10 is (0,0) i.e. current pixel.
             // 	-2 | -1|  0| +1| +2 	(x)
@@ -134,111 +509,291 @@ pub fn apply(buf: &mut [u32], image: &[u32], width: u32, height: u32) {
             matrix[20] = pixel_at(x + 1, y + 2);
 
             // Calculate color weights using 2 points in the matrix
-            let d_10_9 = diff(matrix[10], matrix[9]);
-            let d_10_5 = diff(matrix[10], matrix[5]);
-            let d_10_11 = diff(matrix[10], matrix[11]);
-            let d_10_15 = diff(matrix[10], matrix[15]);
-            let d_10_14 = diff(matrix[10], matrix[14]);
-            let d_10_6 = diff(matrix[10], matrix[6]);
-            let d_4_8 = diff(matrix[4], matrix[8]);
-            let d_4_1 = diff(matrix[4], matrix[1]);
-            let d_9_5 = diff(matrix[9], matrix[5]);
-            let d_9_15 = diff(matrix[9], matrix[15]);
-            let d_9_3 = diff(matrix[9], matrix[3]);
-            let d_5_11 = diff(matrix[5], matrix[11]);
-            let d_5_0 = diff(matrix[5], matrix[0]);
-            let d_10_4 = diff(matrix[10], matrix[4]);
-            let d_10_16 = diff(matrix[10], matrix[16]);
-            let d_6_12 = diff(matrix[6], matrix[12]);
-            let d_6_1 = diff(matrix[6], matrix[1]);
-            let d_11_15 = diff(matrix[11], matrix[15]);
-            let d_11_7 = diff(matrix[11], matrix[7]);
-            let d_5_2 = diff(matrix[5], matrix[2]);
-            let d_14_8 = diff(matrix[14], matrix[8]);
-            let d_14_19 = diff(matrix[14], matrix[19]);
-            let d_15_18 = diff(matrix[15], matrix[18]);
-            let d_9_13 = diff(matrix[9], matrix[13]);
-            let d_16_12 = diff(matrix[16], matrix[12]);
-            let d_16_19 = diff(matrix[16], matrix[19]);
-            let d_15_20 = diff(matrix[15], matrix[20]);
-            let d_15_17 = diff(matrix[15], matrix[17]);
+            let d_10_9 = diff(matrix[10], matrix[9], config);
+            let d_10_5 = diff(matrix[10], matrix[5], config);
+            let d_10_11 = diff(matrix[10], matrix[11], config);
+            let d_10_15 = diff(matrix[10], matrix[15], config);
+            let d_10_14 = diff(matrix[10], matrix[14], config);
+            let d_10_6 = diff(matrix[10], matrix[6], config);
+            let d_4_8 = diff(matrix[4], matrix[8], config);
+            let d_4_1 = diff(matrix[4], matrix[1], config);
+            let d_9_5 = diff(matrix[9], matrix[5], config);
+            let d_9_15 = diff(matrix[9], matrix[15], config);
+            let d_9_3 = diff(matrix[9], matrix[3], config);
+            let d_5_11 = diff(matrix[5], matrix[11], config);
+            let d_5_0 = diff(matrix[5], matrix[0], config);
+            let d_10_4 = diff(matrix[10], matrix[4], config);
+            let d_10_16 = diff(matrix[10], matrix[16], config);
+            let d_6_12 = diff(matrix[6], matrix[12], config);
+            let d_6_1 = diff(matrix[6], matrix[1], config);
+            let d_11_15 = diff(matrix[11], matrix[15], config);
+            let d_11_7 = diff(matrix[11], matrix[7], config);
+            let d_5_2 = diff(matrix[5], matrix[2], config);
+            let d_14_8 = diff(matrix[14], matrix[8], config);
+            let d_14_19 = diff(matrix[14], matrix[19], config);
+            let d_15_18 = diff(matrix[15], matrix[18], config);
+            let d_9_13 = diff(matrix[9], matrix[13], config);
+            let d_16_12 = diff(matrix[16], matrix[12], config);
+            let d_16_19 = diff(matrix[16], matrix[19], config);
+            let d_15_20 = diff(matrix[15], matrix[20], config);
+            let d_15_17 = diff(matrix[15], matrix[17], config);
 
             // Top Left Edge Detection Rule
             let a1 = d_10_14 + d_10_6 + d_4_8 + d_4_1 + 4.0 * d_9_5;
             let b1 = d_9_15 + d_9_3 + d_5_11 + d_5_0 + 4.0 * d_10_4;
-            let idx = ((y * SCALE) * scaled_width) + (x * SCALE);
-
-            buf[idx as usize] = if a1 < b1 {
-                let new_pixel = if d_10_9 <= d_10_5 {
+            let edge_pixel1 = if a1 < b1 {
+                Some(if d_10_9 <= d_10_5 {
                     matrix[9]
                 } else {
                     matrix[5]
-                };
-                let blended_pixel = blend(new_pixel, matrix[10], 0.5);
-                blended_pixel
+                })
             } else {
-                matrix[10]
+                None
             };
+            write_quadrant(
+                buf,
+                scaled_width,
+                (x, local_y),
+                (0, 0),
+                matrix[10],
+                edge_pixel1,
+                params,
+            );
 
             // Top Right Edge Detection Rule
             let a2 = d_10_16 + d_10_4 + d_6_12 + d_6_1 + 4.0 * d_5_11;
             let b2 = d_11_15 + d_11_7 + d_9_5 + d_5_2 + 4.0 * d_10_6;
-            let idx = ((y * SCALE) * scaled_width) + (x * SCALE + 1);
-            buf[idx as usize] = if a2 < b2 {
-                let new_pixel = if d_10_5 <= d_10_11 {
+            let edge_pixel2 = if a2 < b2 {
+                Some(if d_10_5 <= d_10_11 {
                     matrix[5]
                 } else {
                     matrix[11]
-                };
-                let blended_pixel = blend(new_pixel, matrix[10], 0.5);
-                blended_pixel
+                })
             } else {
-                matrix[10]
+                None
             };
+            write_quadrant(
+                buf,
+                scaled_width,
+                (x, local_y),
+                (1, 0),
+                matrix[10],
+                edge_pixel2,
+                params,
+            );
 
             // Bottom Left Edge Detection Rule
             let a3 = d_10_4 + d_10_16 + d_14_8 + d_14_19 + 4.0 * d_9_15;
             let b3 = d_9_5 + d_9_13 + d_11_15 + d_15_18 + 4.0 * d_10_14;
-            let idx = ((y * SCALE + 1) * scaled_width) + (x * SCALE);
-            buf[idx as usize] = if a3 < b3 {
-                let new_pixel = if d_10_9 <= d_10_15 {
+            let edge_pixel3 = if a3 < b3 {
+                Some(if d_10_9 <= d_10_15 {
                     matrix[9]
                 } else {
                     matrix[15]
-                };
-                let blended_pixel = blend(new_pixel, matrix[10], 0.5);
-                blended_pixel
+                })
             } else {
-                matrix[10]
+                None
             };
+            write_quadrant(
+                buf,
+                scaled_width,
+                (x, local_y),
+                (0, 1),
+                matrix[10],
+                edge_pixel3,
+                params,
+            );
 
             // Bottom Right Edge Detection Rule
             let a4 = d_10_6 + d_10_14 + d_16_12 + d_16_19 + 4.0 * d_11_15;
             let b4 = d_9_15 + d_15_20 + d_15_17 + d_5_11 + 4.0 * d_10_16;
-            let idx = ((y * SCALE + 1) * scaled_width) + (x * SCALE + 1);
-            buf[idx as usize] = if a4 < b4 {
-                let new_pixel = if d_10_11 <= d_10_15 {
+            let edge_pixel4 = if a4 < b4 {
+                Some(if d_10_11 <= d_10_15 {
                     matrix[11]
                 } else {
                     matrix[15]
-                };
-                let blended_pixel = blend(new_pixel, matrix[10], 0.5);
-                blended_pixel
+                })
             } else {
-                matrix[10]
+                None
             };
+            write_quadrant(
+                buf,
+                scaled_width,
+                (x, local_y),
+                (1, 1),
+                matrix[10],
+                edge_pixel4,
+                params,
+            );
         }
     }
 }
 
-pub fn get_buffer_for_size(width: u32, height: u32) -> (Vec<u32>, u32, u32) {
+/// Allocates an output buffer sized for upscaling `width x height` by `scale`.
+pub fn get_buffer_for_size<C: Color>(
+    width: u32,
+    height: u32,
+    scale: u32,
+) -> (Vec<C::Buffer>, u32, u32) {
     (
-        vec![0; (width as usize) * 2 * (height as usize) * 2],
-        width * 2,
-        height * 2,
+        vec![C::Buffer::default(); (width * scale) as usize * (height * scale) as usize],
+        width * scale,
+        height * scale,
     )
 }
 
+/// Applies the xBR filter like `apply`, but splits the destination into
+/// horizontal bands of rows and processes them concurrently with rayon.
+///
+/// Each band reads from the whole (shared, immutable) `image` slice —
+/// `pixel_at` already clamps out-of-bounds reads to the nearest edge pixel,
+/// so the 2-pixel halo a band needs from its neighbors is always available
+/// without copying — and writes into its own disjoint sub-slice of `buf`.
+/// `threads` pins the band count to a specific worker count; `None` uses
+/// rayon's global thread pool as-is.
+pub fn apply_parallel<C>(
+    buf: &mut [C::Buffer],
+    image: &[C],
+    width: u32,
+    height: u32,
+    threads: Option<usize>,
+) where
+    C: Color + Sync,
+    C::Buffer: Send,
+{
+    apply_parallel_with_config(buf, image, width, height, threads, &XbrConfig::default());
+}
+
+/// `apply_parallel`, but with custom edge-sensitivity/blend-strength weights.
+pub fn apply_parallel_with_config<C>(
+    buf: &mut [C::Buffer],
+    image: &[C],
+    width: u32,
+    height: u32,
+    threads: Option<usize>,
+    config: &XbrConfig,
+) where
+    C: Color + Sync,
+    C::Buffer: Send,
+{
+    use rayon::prelude::*;
+
+    const SCALE: usize = 2;
+    let row_stride = (width as usize) * SCALE * SCALE;
+
+    let mut run = |num_bands: usize| {
+        let band_rows = ((height as usize) + num_bands - 1) / num_bands.max(1);
+        let band_rows = band_rows.max(1);
+
+        buf.par_chunks_mut(band_rows * row_stride)
+            .enumerate()
+            .for_each(|(band_index, band_buf)| {
+                let y_start = (band_index * band_rows) as i32;
+                let y_end = ((band_index + 1) * band_rows).min(height as usize) as i32;
+                apply_rows(
+                    band_buf,
+                    image,
+                    width,
+                    height,
+                    (y_start, y_end),
+                    &RowParams { config, scale: 2 },
+                );
+            });
+    };
+
+    match threads {
+        Some(num_threads) => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .expect("failed to build the xBR thread pool");
+            pool.install(|| run(num_threads.max(1)));
+        }
+        None => run(rayon::current_num_threads()),
+    }
+}
+
+fn u8_channel(normalized: f32) -> u8 {
+    (normalized * 255.0).round() as u8
+}
+
+/// Applies the xBR filter to any format the `image` crate can decode
+/// (PNG, JPEG, BMP, ...), returning an upscaled image. `Luma8`, `LumaA8`,
+/// `Rgb8` and `Rgba8` sources come back in their own format; every other
+/// format is converted to and returned as `Rgba8`.
+///
+/// Grayscale sources (`Luma8`/`LumaA8`) are widened into the internal RGBA
+/// buffer, filtered, then narrowed back down rather than being promoted to
+/// a color output, so callers don't have to write their own decode/encode
+/// glue just to round-trip a single image file.
+pub fn apply_dynamic(img: &image::DynamicImage) -> image::DynamicImage {
+    use image::{DynamicImage, GrayAlphaImage, GrayImage, RgbImage, RgbaImage};
+
+    let width = img.width();
+    let height = img.height();
+
+    let input: Vec<u32> = match img {
+        DynamicImage::ImageLuma8(buf) => buf
+            .pixels()
+            .map(|p| color_u8_to_u32(p[0], p[0], p[0]))
+            .collect(),
+        DynamicImage::ImageLumaA8(buf) => buf
+            .pixels()
+            .map(|p| color_rgba_u8_to_u32(p[0], p[0], p[0], p[1]))
+            .collect(),
+        DynamicImage::ImageRgb8(buf) => buf
+            .pixels()
+            .map(|p| color_u8_to_u32(p[0], p[1], p[2]))
+            .collect(),
+        DynamicImage::ImageRgba8(buf) => buf
+            .pixels()
+            .map(|p| color_rgba_u8_to_u32(p[0], p[1], p[2], p[3]))
+            .collect(),
+        other => other
+            .to_rgba8()
+            .pixels()
+            .map(|p| color_rgba_u8_to_u32(p[0], p[1], p[2], p[3]))
+            .collect(),
+    };
+
+    let (mut out_buf, out_width, out_height) = get_buffer_for_size::<u32>(width, height, 2);
+    apply(&mut out_buf[..], &input, width, height);
+
+    match img {
+        DynamicImage::ImageLuma8(_) => {
+            DynamicImage::ImageLuma8(GrayImage::from_fn(out_width, out_height, |x, y| {
+                let pixel = out_buf[(y * out_width + x) as usize];
+                image::Luma([u8_channel(pixel.red_f32())])
+            }))
+        }
+        DynamicImage::ImageLumaA8(_) => {
+            DynamicImage::ImageLumaA8(GrayAlphaImage::from_fn(out_width, out_height, |x, y| {
+                let pixel = out_buf[(y * out_width + x) as usize];
+                image::LumaA([u8_channel(pixel.red_f32()), u8_channel(pixel.alpha_f32())])
+            }))
+        }
+        DynamicImage::ImageRgb8(_) => {
+            DynamicImage::ImageRgb8(RgbImage::from_fn(out_width, out_height, |x, y| {
+                let pixel = out_buf[(y * out_width + x) as usize];
+                image::Rgb([
+                    u8_channel(pixel.red_f32()),
+                    u8_channel(pixel.green_f32()),
+                    u8_channel(pixel.blue_f32()),
+                ])
+            }))
+        }
+        _ => DynamicImage::ImageRgba8(RgbaImage::from_fn(out_width, out_height, |x, y| {
+            let pixel = out_buf[(y * out_width + x) as usize];
+            image::Rgba([
+                u8_channel(pixel.red_f32()),
+                u8_channel(pixel.green_f32()),
+                u8_channel(pixel.blue_f32()),
+                u8_channel(pixel.alpha_f32()),
+            ])
+        })),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -259,18 +814,153 @@ mod tests {
                 .map(|i| color_u8_to_u32(img[i * 3 + 0], img[i * 3 + 1], img[i * 3 + 2]))
                 .collect(),
             png::ColorType::RGBA => (0..(info.width * info.height) as usize)
-                .map(|i| color_u8_to_u32(img[i * 4 + 0], img[i * 4 + 1], img[i * 4 + 2]))
+                .map(|i| {
+                    color_rgba_u8_to_u32(
+                        img[i * 4 + 0],
+                        img[i * 4 + 1],
+                        img[i * 4 + 2],
+                        img[i * 4 + 3],
+                    )
+                })
                 .collect(),
             _ => unimplemented!(),
         };
 
-        let (mut out_buf, out_width, out_height) = get_buffer_for_size(info.width, info.height);
+        let (mut out_buf, out_width, out_height) =
+            get_buffer_for_size::<u32>(info.width, info.height, 2);
         b.iter(|| apply(&mut out_buf[..], &input, info.width, info.height));
 
         save_img("./assets/output.png", out_width, out_height, &out_buf[..])
             .expect("Could not save output image");
     }
 
+    #[bench]
+    fn bench_xbr_parallel(b: &mut Bencher) {
+        let (img, info) = load_img("./assets/input.png").expect("Could not load input image");
+
+        let input: Vec<u32> = match info.color_type {
+            png::ColorType::RGB => (0..(info.width * info.height) as usize)
+                .map(|i| color_u8_to_u32(img[i * 3 + 0], img[i * 3 + 1], img[i * 3 + 2]))
+                .collect(),
+            png::ColorType::RGBA => (0..(info.width * info.height) as usize)
+                .map(|i| {
+                    color_rgba_u8_to_u32(
+                        img[i * 4 + 0],
+                        img[i * 4 + 1],
+                        img[i * 4 + 2],
+                        img[i * 4 + 3],
+                    )
+                })
+                .collect(),
+            _ => unimplemented!(),
+        };
+
+        let (mut out_buf, out_width, out_height) =
+            get_buffer_for_size::<u32>(info.width, info.height, 2);
+        b.iter(|| apply_parallel(&mut out_buf[..], &input, info.width, info.height, None));
+
+        save_img(
+            "./assets/output_parallel.png",
+            out_width,
+            out_height,
+            &out_buf[..],
+        )
+        .expect("Could not save output image");
+    }
+
+    #[test]
+    fn diff_index_equality_treats_mismatched_indices_as_hard_edge() {
+        let config = XbrConfig {
+            index_equality: true,
+            ..XbrConfig::default()
+        };
+        let a = IndexedColor {
+            index: 0,
+            color: 0xFF112233,
+        };
+        let b = IndexedColor {
+            index: 1,
+            color: 0xFF112233,
+        };
+
+        assert_eq!(diff(a, a, &config), 0.0);
+        assert!(diff(a, b, &config) > 1.0e6);
+    }
+
+    #[test]
+    fn diff_index_equality_ignores_color_when_indices_match() {
+        let config = XbrConfig {
+            index_equality: true,
+            ..XbrConfig::default()
+        };
+        let a = IndexedColor {
+            index: 5,
+            color: 0xFF000000,
+        };
+        let b = IndexedColor {
+            index: 5,
+            color: 0xFFFFFFFF,
+        };
+
+        assert_eq!(diff(a, b, &config), 0.0);
+    }
+
+    #[test]
+    fn subpixel_half_splits_first_and_last_subpixel_into_different_halves() {
+        for scale in [2u32, 3, 4] {
+            assert_eq!(subpixel_half(0, scale), 0);
+            assert_eq!(subpixel_half(scale - 1, scale), 1);
+        }
+    }
+
+    #[test]
+    fn apply_scale_keeps_flat_regions_flat_at_every_scale() {
+        let width = 4;
+        let height = 4;
+        let image: Vec<u32> = vec![0xFF804020; (width * height) as usize];
+
+        for scale in [2u32, 3, 4] {
+            let (mut buf, out_width, out_height) = get_buffer_for_size::<u32>(width, height, scale);
+            apply_scale(&mut buf, &image, width, height, scale);
+
+            assert_eq!(out_width, width * scale);
+            assert_eq!(out_height, height * scale);
+            assert!(buf.iter().all(|&pixel| pixel == 0xFF804020));
+        }
+    }
+
+    #[test]
+    fn apply_scale_at_2x_matches_apply() {
+        let width = 4;
+        let height = 4;
+        let image: Vec<u32> = (0..(width * height))
+            .map(|i| color_u8_to_u32((i * 17) as u8, (i * 31) as u8, (i * 53) as u8))
+            .collect();
+
+        let (mut expected, _, _) = get_buffer_for_size::<u32>(width, height, 2);
+        apply(&mut expected, &image, width, height);
+
+        let (mut actual, _, _) = get_buffer_for_size::<u32>(width, height, 2);
+        apply_scale(&mut actual, &image, width, height, 2);
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn apply_rgba64_round_trip_keeps_flat_regions_flat() {
+        let width = 3;
+        let height = 3;
+        let color = Rgba64::new(color_rgba_u16_to_u64(0x1234, 0x5678, 0x9ABC, 0xFFFF));
+        let image: Vec<Rgba64> = vec![color; (width * height) as usize];
+
+        let (mut buf, out_width, out_height) = get_buffer_for_size::<Rgba64>(width, height, 2);
+        apply(&mut buf, &image, width, height);
+
+        assert_eq!(out_width, width * 2);
+        assert_eq!(out_height, height * 2);
+        assert!(buf.iter().all(|&pixel| pixel == color));
+    }
+
     fn load_img(path: &str) -> Result<(Vec<u8>, png::OutputInfo), std::io::Error> {
         let file = File::open(Path::new(path))?;
         let ref mut r = BufReader::new(file);
@@ -283,9 +973,17 @@ mod tests {
         Ok((buf, info))
     }
 
-    fn explode_rgb(buf: &[u32]) -> Vec<u8> {
-        (0..buf.len() * 3)
-            .map(|i| ((buf[(i / 3)] >> (8 * (2 - (i % 3)))) & 0xFF) as u8)
+    fn explode_rgba(buf: &[u32]) -> Vec<u8> {
+        (0..buf.len() * 4)
+            .map(|i| {
+                let pixel = buf[i / 4];
+                match i % 4 {
+                    0 => (pixel.red_f32() * 255.0).round() as u8,
+                    1 => (pixel.green_f32() * 255.0).round() as u8,
+                    2 => (pixel.blue_f32() * 255.0).round() as u8,
+                    _ => (pixel.alpha_f32() * 255.0).round() as u8,
+                }
+            })
             .collect()
     }
 
@@ -294,14 +992,14 @@ mod tests {
         let ref mut w = BufWriter::new(file);
         let mut encoder = png::Encoder::new(w, width, height);
 
-        encoder.set_color(png::ColorType::RGB);
+        encoder.set_color(png::ColorType::RGBA);
         encoder.set_depth(png::BitDepth::Eight);
         encoder.set_compression(png::Compression::Default);
         encoder.set_filter(png::FilterType::NoFilter);
 
         let mut writer = encoder.write_header()?;
 
-        writer.write_image_data(&explode_rgb(data)[..])?;
+        writer.write_image_data(&explode_rgba(data)[..])?;
 
         Ok(())
     }